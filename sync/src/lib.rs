@@ -16,14 +16,16 @@
 //! use std::sync::Arc;
 //! use util::network::{NetworkService, NetworkConfiguration};
 //! use ethcore::client::Client;
-//! use ethsync::EthSync;
+//! use ethsync::{EthSync, SyncConfig};
 //! use ethcore::ethereum;
 //!
 //! fn main() {
-//! 	let mut service = NetworkService::start(NetworkConfiguration::new()).unwrap();
+//! 	let sync_config = SyncConfig::default();
+//! 	let net_config = sync_config.network_configuration();
+//! 	let service = NetworkService::start(net_config.clone()).unwrap();
 //! 	let dir = env::temp_dir();
 //! 	let client = Client::new(ethereum::new_frontier(), &dir, service.io().channel()).unwrap();
-//! 	EthSync::register(&mut service, client);
+//! 	let (_sync, _manager) = EthSync::register(service, net_config, client, sync_config).unwrap();
 //! }
 //! ```
 
@@ -37,13 +39,18 @@ extern crate time;
 
 use std::ops::*;
 use std::sync::*;
-use ethcore::client::Client;
-use util::network::{NetworkProtocolHandler, NetworkService, NetworkContext, PeerId};
-use util::io::TimerToken;
+use ethcore::client::{Client, ChainNotify};
+use util::network::{NetworkProtocolHandler, NetworkService, NetworkContext, NetworkConfiguration, NetworkError, PeerId};
+use util::io::{TimerToken, IoService, IoHandler, IoContext, IoError};
+use util::hash::H256;
+use util::uint::U256;
 use chain::ChainSync;
 use ethcore::service::SyncMessage;
 use io::NetSyncIo;
 
+/// Token used to drive periodic peer maintenance on the internal IO service.
+const MAINTAIN_SYNC_TIMER: TimerToken = 0;
+
 mod chain;
 mod io;
 mod range_collection;
@@ -51,62 +58,294 @@ mod range_collection;
 #[cfg(test)]
 mod tests;
 
-/// Ethereum network protocol handler
-pub struct EthSync {
+/// Shared sync state driven both by inbound network events and by the
+/// internal IO service. Held behind an `Arc` so that the network protocol
+/// handler and the off-thread IO handler operate on the same strategy.
+struct SyncCore {
 	/// Shared blockchain client. TODO: this should evetually become an IPC endpoint
 	chain: Arc<Client>,
 	/// Sync strategy
-	sync: RwLock<ChainSync>
+	sync: RwLock<ChainSync>,
+}
+
+/// Ethereum network protocol handler
+pub struct EthSync {
+	/// Shared sync state
+	state: Arc<SyncCore>,
+	/// Internal IO service used to drain heavy import/propagation work off the
+	/// network dispatch thread.
+	io_service: IoService<SyncIoMessage>,
+}
+
+/// Messages posted by the network callbacks and drained off the network
+/// dispatch thread by the sync IO handler, so that block validation, queue
+/// insertion and propagation no longer run under the `ChainSync` write lock on
+/// the network reactor.
+enum SyncIoMessage {
+	/// A peer connected.
+	NewPeer(PeerId),
+	/// A packet arrived from a peer.
+	PacketArrived(PeerId, u8, Vec<u8>),
+	/// A peer is disconnecting.
+	PeerAborting(PeerId),
 }
 
 pub use self::chain::SyncStatus;
 
+/// Default number of peers the sync layer advertises a willingness to accept.
+const DEFAULT_MAX_PEERS: u32 = 25;
+
+/// Sync and network configuration, constructed by the caller (including a
+/// future IPC manage endpoint) so that the advertised protocol versions and
+/// network parameters can be chosen at runtime instead of being hard-coded.
+#[derive(Clone, Binary)]
+pub struct SyncConfig {
+	/// Advertised ethereum sub-protocol versions, most preferred last.
+	pub protocol_versions: Vec<u8>,
+	/// Network listen address, or `None` for the default.
+	pub listen_address: Option<String>,
+	/// Boot node enodes to connect to on start.
+	pub boot_nodes: Vec<String>,
+	/// Maximum number of peers to maintain.
+	pub max_peers: u32,
+}
+
+impl Default for SyncConfig {
+	fn default() -> SyncConfig {
+		SyncConfig {
+			protocol_versions: vec![62u8, 63u8],
+			listen_address: None,
+			boot_nodes: Vec::new(),
+			max_peers: DEFAULT_MAX_PEERS,
+		}
+	}
+}
+
+impl SyncConfig {
+	/// Network configuration advertised by this sync config. The caller starts
+	/// the `NetworkService` with this so that the listen address, boot nodes
+	/// and peer count carried here actually take effect.
+	pub fn network_configuration(&self) -> NetworkConfiguration {
+		let mut net = NetworkConfiguration::new();
+		net.listen_address = self.listen_address.as_ref().and_then(|addr| addr.parse().ok());
+		net.boot_nodes = self.boot_nodes.clone();
+		net.ideal_peers = self.max_peers;
+		net
+	}
+}
+
+/// Errors that can occur while registering a sync instance.
+#[derive(Debug)]
+pub enum SyncConfigError {
+	/// A configured protocol version is not one `ChainSync` can speak.
+	UnsupportedProtocolVersion(u8),
+	/// The internal sync IO service could not be started or driven.
+	Io(IoError),
+	/// The protocol handler could not be registered with the network.
+	Network(NetworkError),
+}
+
+impl From<IoError> for SyncConfigError {
+	fn from(err: IoError) -> SyncConfigError {
+		SyncConfigError::Io(err)
+	}
+}
+
+impl From<NetworkError> for SyncConfigError {
+	fn from(err: NetworkError) -> SyncConfigError {
+		SyncConfigError::Network(err)
+	}
+}
+
+/// Connection state of a peer as tracked by the sync strategy.
+#[derive(Binary, Clone, Copy, PartialEq, Eq)]
+pub enum PeerConnectionState {
+	/// Peer is connected and idle.
+	Connected,
+	/// A request is currently in flight to the peer.
+	Syncing,
+}
+
+/// Peer connection information, as exposed over the sync provider interface.
+#[derive(Binary)]
+pub struct PeerInfo {
+	/// Public node id
+	pub id: Option<String>,
+	/// Negotiated ethereum protocol version
+	pub eth_version: u32,
+	/// Peer total difficulty
+	pub eth_difficulty: U256,
+	/// Peer best block hash
+	pub eth_head: H256,
+	/// Peer connection state
+	pub connection_state: PeerConnectionState,
+}
+
+/// Sync status and peer information, exposed over IPC so that out-of-process
+/// callers (e.g. the RPC process) can query sync health without sharing the
+/// client's address space.
+#[ipc]
+pub trait SyncProvider: Send + Sync {
+	/// Get sync status
+	fn status(&self) -> SyncStatus;
+
+	/// Get peers information
+	fn peers(&self) -> Vec<PeerInfo>;
+}
+
+impl SyncProvider for EthSync {
+	/// Get sync status
+	fn status(&self) -> SyncStatus {
+		self.state.sync.read().unwrap().status()
+	}
+
+	/// Get peers information
+	fn peers(&self) -> Vec<PeerInfo> {
+		self.state.sync.read().unwrap().peers()
+	}
+}
+
 impl EthSync {
-	/// Creates and register protocol with the network service
-	pub fn register(service: &mut NetworkService<SyncMessage>, chain: Arc<Client>) -> Arc<EthSync> {
+	/// Creates and registers the protocol with the network service, taking
+	/// ownership of it so that an out-of-process `ManageNetwork` handle can be
+	/// handed back alongside the sync provider.
+	pub fn register(mut network: NetworkService<SyncMessage>, network_config: NetworkConfiguration, chain: Arc<Client>, config: SyncConfig) -> Result<(Arc<EthSync>, Arc<NetworkManager>), SyncConfigError> {
+		for version in &config.protocol_versions {
+			if !ChainSync::supported_versions().contains(version) {
+				return Err(SyncConfigError::UnsupportedProtocolVersion(*version));
+			}
+		}
 		let sync = Arc::new(EthSync {
-			chain: chain,
-			sync: RwLock::new(ChainSync::new()),
+			state: Arc::new(SyncCore {
+				chain: chain.clone(),
+				sync: RwLock::new(ChainSync::new()),
+			}),
+			io_service: try!(IoService::<SyncIoMessage>::start()),
 		});
-		service.register_protocol(sync.clone(), "eth", &[62u8, 63u8]).expect("Error registering eth protocol handler");
-		sync
+		try!(sync.io_service.register_handler(Arc::new(SyncIoHandler { state: sync.state.clone() })));
+		try!(network.register_protocol(sync.clone(), "eth", &config.protocol_versions));
+		chain.register_notify(sync.clone());
+		let manager = Arc::new(NetworkManager::new(network, network_config));
+		Ok((sync, manager))
+	}
+}
+
+impl NetworkProtocolHandler<SyncMessage> for EthSync {
+	fn initialize(&self, _io: &NetworkContext<SyncMessage>) {
+		self.io_service.register_timer(MAINTAIN_SYNC_TIMER, 1000).expect("Error registering sync timer");
 	}
 
-	/// Get sync status
-	pub fn status(&self) -> SyncStatus {
-		self.sync.read().unwrap().status()
+	fn read(&self, _io: &NetworkContext<SyncMessage>, peer: &PeerId, packet_id: u8, data: &[u8]) {
+		self.io_service.send_message(SyncIoMessage::PacketArrived(*peer, packet_id, data.to_vec()))
+			.unwrap_or_else(|e| warn!("Error queueing sync packet: {:?}", e));
 	}
 
-	/// Stop sync
-	pub fn stop(&mut self, io: &mut NetworkContext<SyncMessage>) {
-		self.sync.write().unwrap().abort(&mut NetSyncIo::new(io, self.chain.deref()));
+	fn connected(&self, _io: &NetworkContext<SyncMessage>, peer: &PeerId) {
+		self.io_service.send_message(SyncIoMessage::NewPeer(*peer))
+			.unwrap_or_else(|e| warn!("Error queueing peer connection: {:?}", e));
 	}
 
-	/// Restart sync
-	pub fn restart(&mut self, io: &mut NetworkContext<SyncMessage>) {
-		self.sync.write().unwrap().restart(&mut NetSyncIo::new(io, self.chain.deref()));
+	fn disconnected(&self, _io: &NetworkContext<SyncMessage>, peer: &PeerId) {
+		self.io_service.send_message(SyncIoMessage::PeerAborting(*peer))
+			.unwrap_or_else(|e| warn!("Error queueing peer disconnection: {:?}", e));
 	}
 }
 
-impl NetworkProtocolHandler<SyncMessage> for EthSync {
-	fn initialize(&self, io: &NetworkContext<SyncMessage>) {
-		io.register_timer(0, 1000).expect("Error registering sync timer");
+impl ChainNotify for EthSync {
+	fn new_blocks(&self, imported: Vec<H256>, _invalid: Vec<H256>, enacted: Vec<H256>, retracted: Vec<H256>, sealed: Vec<H256>) {
+		self.state.chain.network_context().map(|mut io| {
+			let mut sync_io = NetSyncIo::new(&mut io, self.state.chain.deref());
+			self.state.sync.write().unwrap().chain_new_blocks(&mut sync_io, &imported, &enacted, &retracted, &sealed);
+		});
+	}
+
+	fn start(&self) {}
+
+	fn stop(&self) {}
+}
+
+/// Drains sync work enqueued by the network callbacks off the network
+/// dispatch thread, so that block validation, queue insertion and
+/// propagation no longer block the network reactor.
+struct SyncIoHandler {
+	state: Arc<SyncCore>,
+}
+
+impl IoHandler<SyncIoMessage> for SyncIoHandler {
+	fn message(&self, _io: &IoContext<SyncIoMessage>, message: &SyncIoMessage) {
+		self.state.chain.network_context().map(|mut io| {
+			let mut sync_io = NetSyncIo::new(&mut io, self.state.chain.deref());
+			let mut sync = self.state.sync.write().unwrap();
+			match *message {
+				SyncIoMessage::NewPeer(peer) => sync.on_peer_connected(&mut sync_io, peer),
+				SyncIoMessage::PacketArrived(peer, packet_id, ref data) => sync.on_packet(&mut sync_io, peer, packet_id, data),
+				SyncIoMessage::PeerAborting(peer) => sync.on_peer_aborting(&mut sync_io, peer),
+			}
+		});
+	}
+
+	fn timeout(&self, _io: &IoContext<SyncIoMessage>, timer: TimerToken) {
+		if timer == MAINTAIN_SYNC_TIMER {
+			self.state.chain.network_context().map(|mut io| {
+				let mut sync_io = NetSyncIo::new(&mut io, self.state.chain.deref());
+				self.state.sync.write().unwrap().maintain_peers(&mut sync_io);
+			});
+		}
 	}
+}
+
+/// Network management. Gives out-of-process callers (an operator or an RPC
+/// endpoint) a way to pause syncing, query the number of connected peers and
+/// drop a peer on demand, without needing an active protocol callback.
+#[ipc]
+pub trait ManageNetwork: Send + Sync {
+	/// Start the network layer.
+	fn start_network(&self);
+	/// Stop the network layer.
+	fn stop_network(&self);
+	/// Number of currently connected peers.
+	fn num_peers(&self) -> usize;
+	/// Disconnect a peer.
+	fn disconnect_peer(&self, peer: PeerId);
+}
+
+/// Owns the network service so that it can be started and stopped
+/// independently of the protocol callbacks. The service is always restarted
+/// with the configuration given to `new`; that configuration cannot be
+/// changed afterwards.
+pub struct NetworkManager {
+	/// Network service handle.
+	network: NetworkService<SyncMessage>,
+	/// Configuration the service was started with, retained so it can be
+	/// restarted by a remote caller.
+	config: NetworkConfiguration,
+}
+
+impl NetworkManager {
+	/// Take ownership of a started network service and its configuration.
+	pub fn new(network: NetworkService<SyncMessage>, config: NetworkConfiguration) -> NetworkManager {
+		NetworkManager {
+			network: network,
+			config: config,
+		}
+	}
+}
 
-	fn read(&self, io: &NetworkContext<SyncMessage>, peer: &PeerId, packet_id: u8, data: &[u8]) {
-		self.sync.write().unwrap().on_packet(&mut NetSyncIo::new(io, self.chain.deref()) , *peer, packet_id, data);
+impl ManageNetwork for NetworkManager {
+	fn start_network(&self) {
+		self.network.start(self.config.clone()).expect("Error starting network service");
 	}
 
-	fn connected(&self, io: &NetworkContext<SyncMessage>, peer: &PeerId) {
-		self.sync.write().unwrap().on_peer_connected(&mut NetSyncIo::new(io, self.chain.deref()), *peer);
+	fn stop_network(&self) {
+		self.network.stop();
 	}
 
-	fn disconnected(&self, io: &NetworkContext<SyncMessage>, peer: &PeerId) {
-		self.sync.write().unwrap().on_peer_aborting(&mut NetSyncIo::new(io, self.chain.deref()), *peer);
+	fn num_peers(&self) -> usize {
+		self.network.connected_peers()
 	}
 
-	fn timeout(&self, io: &NetworkContext<SyncMessage>, _timer: TimerToken) {
-		self.sync.write().unwrap().maintain_peers(&mut NetSyncIo::new(io, self.chain.deref()));
+	fn disconnect_peer(&self, peer: PeerId) {
+		self.network.disconnect_peer(peer);
 	}
 }
 